@@ -1,12 +1,28 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use log::info;
 use prost::Message;
 use rand::prelude::IteratorRandom;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use flate2::read::GzDecoder;
+use memmap2::Mmap;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::Message as KafkaMessage;
 use std::fs::File;
-use std::io::{self, BufReader, Read, Result as IoResult};
+use std::io::{self, BufRead, BufReader, Read, Result as IoResult};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::vec;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 use tonic::{transport::Server, Request, Response, Status};
 
 pub mod text_data {
@@ -15,65 +31,314 @@ pub mod text_data {
 
 use text_data::{
     data_service_server::{DataService, DataServiceServer},
-    SampleDataRequest, SampledData, Sentence, TextData,
+    EpochBatch, NextEpochBatchRequest, ResetEpochRequest, ResetEpochResponse, SampleDataRequest,
+    SampledData, Sentence, StreamSamplesRequest, TextData,
 };
 
+/// Backing storage for one loaded `--files` entry: a memory map for plain
+/// `.pb` shards, or an owned buffer for shards that had to be decompressed
+/// up front (compressed streams aren't randomly seekable on disk).
+enum FileBacking {
+    Mmap(Mmap),
+    Buffer(Vec<u8>),
+}
+
+impl Deref for FileBacking {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBacking::Mmap(mmap) => &mmap[..],
+            FileBacking::Buffer(buf) => &buf[..],
+        }
+    }
+}
+
+/// Where one `TextData` group lives within a `FileBacking`, so it can be
+/// decoded on demand instead of being kept resident in `groups`.
+struct GroupLocation {
+    file_id: usize,
+    offset: usize,
+    length: usize,
+    sentence_count: u32,
+}
+
+/// How a group's sampling weight is derived from its sentence count. Plain
+/// `Linear` weighting lets a handful of large groups (e.g. a long book)
+/// dominate sampling; `Sqrt`/`Temperature` dampen that, `Uniform` ignores
+/// group size entirely.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum WeightingStrategy {
+    Linear,
+    Sqrt,
+    Uniform,
+    Temperature,
+}
+
+fn group_weight(sentence_count: u32, strategy: WeightingStrategy, temperature: f32) -> f32 {
+    let len = sentence_count as f32;
+    match strategy {
+        WeightingStrategy::Linear => len,
+        WeightingStrategy::Sqrt => len.sqrt(),
+        WeightingStrategy::Uniform => 1.0,
+        WeightingStrategy::Temperature => len.powf(1.0 / temperature),
+    }
+}
+
+/// Global, shuffled (group_index, sentence_index) permutation for
+/// sampling-without-replacement "epoch" iteration, plus where the next
+/// `next_epoch_batch` call should resume from. The permutation and cursor
+/// are kept behind a single lock so a `reset_epoch` swap and a concurrent
+/// `next_epoch_batch` read-and-advance can never interleave: without this,
+/// a reader could see the new permutation paired with the old cursor.
 #[derive(Default)]
-pub struct MyDataService {
+struct EpochState {
+    permutation_and_cursor: RwLock<(Vec<(u32, u32)>, usize)>,
+}
+
+/// Builds a fresh, seeded shuffle of every (group, sentence) pair in `index`.
+fn build_permutation(index: &[GroupLocation], shuffle_seed: u64) -> Vec<(u32, u32)> {
+    let mut permutation = Vec::new();
+    for (group_idx, location) in index.iter().enumerate() {
+        for sentence_idx in 0..location.sentence_count {
+            permutation.push((group_idx as u32, sentence_idx));
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(shuffle_seed);
+    permutation.shuffle(&mut rng);
+    permutation
+}
+
+/// Groups ingested live (e.g. from Kafka) after startup, kept fully
+/// in-memory since they have no backing file to `mmap`. `weight_sum` and
+/// `version` are maintained incrementally so readers never have to rescan
+/// `weights` just to detect whether it changed.
+#[derive(Default)]
+struct LivePool {
     groups: Vec<TextData>,
     weights: Vec<f32>,
+    weight_sum: f32,
+    version: u64,
 }
 
-fn read_pb_stream<R: Read>(mut reader: BufReader<R>) -> io::Result<Vec<TextData>> {
-    let mut text_data_list = Vec::new();
-    let mut index = 0;
+/// A `WeightedIndex` over the live pool, rebuilt only when `LivePool::version`
+/// has moved past the version it was built from.
+#[derive(Default)]
+struct LiveDistCache {
+    version: u64,
+    dist: Option<WeightedIndex<f32>>,
+}
 
-    loop {
-        let mut size_buf = [0u8; 4];
-        match reader.read_exact(&mut size_buf) {
-            Ok(()) => (),
-            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // End of file
-            Err(e) => return Err(e),
-        }
+#[derive(Default, Clone)]
+pub struct MyDataService {
+    files: Arc<Vec<FileBacking>>,
+    index: Arc<Vec<GroupLocation>>,
+    static_weight_sum: f32,
+    static_dist: Arc<Option<WeightedIndex<f32>>>,
+    epoch: Arc<EpochState>,
+    live: Arc<RwLock<LivePool>>,
+    live_dist_cache: Arc<Mutex<LiveDistCache>>,
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Whether `magic` (the file's leading bytes) or `path`'s extension indicate
+/// a zstd-compressed shard.
+fn looks_zstd(magic: &[u8], path: &str) -> bool {
+    magic.starts_with(&ZSTD_MAGIC) || path.ends_with(".zst")
+}
+
+/// Whether `magic` (the file's leading bytes) or `path`'s extension indicate
+/// a gzip-compressed shard.
+fn looks_gzip(magic: &[u8], path: &str) -> bool {
+    magic.starts_with(&GZIP_MAGIC) || path.ends_with(".gz")
+}
+
+/// Wraps an already-peeked `reader` in the right decompressor for `path`,
+/// based on magic-byte sniffing or file extension. Only called once the
+/// caller has established the shard is compressed.
+fn decompressing_reader(path: &str, mut reader: BufReader<File>) -> io::Result<Box<dyn Read>> {
+    let magic = reader.fill_buf()?;
+    if looks_zstd(magic, path) {
+        Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+    } else {
+        Ok(Box::new(GzDecoder::new(reader)))
+    }
+}
+
+/// Loads one `--files` entry into a `FileBacking`: a zero-copy `mmap` for a
+/// plain `.pb` shard, or a fully decompressed in-memory buffer for a
+/// compressed one (a compressed stream has no stable on-disk offsets to
+/// `mmap` and seek into).
+fn load_file_backing(path: &str) -> io::Result<FileBacking> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file.try_clone()?);
+
+    let magic = reader.fill_buf()?;
+    let compressed = looks_zstd(magic, path) || looks_gzip(magic, path);
+
+    if !compressed {
+        let mmap = unsafe { Mmap::map(&file)? };
+        return Ok(FileBacking::Mmap(mmap));
+    }
+
+    let mut decoder = decompressing_reader(path, reader)?;
+    let mut buf = Vec::new();
+    decoder.read_to_end(&mut buf)?;
+    Ok(FileBacking::Buffer(buf))
+}
 
-        let size = u32::from_le_bytes(size_buf) as usize;
+/// Scans a decoded-once pass over `data`'s length-delimited `TextData`
+/// records, recording a `GroupLocation` (and its sampling weight) for each
+/// one instead of keeping the decoded message around.
+fn index_pb_groups(
+    file_id: usize,
+    data: &[u8],
+    weighting: WeightingStrategy,
+    temperature: f32,
+) -> io::Result<(Vec<GroupLocation>, Vec<f32>)> {
+    let mut locations = Vec::new();
+    let mut weights = Vec::new();
+    let mut cursor = 0usize;
+    let mut count = 0;
 
-        let mut message_buf = vec![0u8; size];
-        reader.read_exact(&mut message_buf)?;
+    while cursor + 4 <= data.len() {
+        let size = u32::from_le_bytes(data[cursor..cursor + 4].try_into().unwrap()) as usize;
+        let start = cursor + 4;
+        let end = start + size;
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated pb shard",
+            ));
+        }
 
-        let text_data = TextData::decode(&message_buf[..])
+        let text_data = TextData::decode(&data[start..end])
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        text_data_list.push(text_data);
+        let sentence_count = text_data.sentences.len() as u32; // Assuming sentences is a repeated field in TextData
+
+        locations.push(GroupLocation {
+            file_id,
+            offset: start,
+            length: size,
+            sentence_count,
+        });
+        weights.push(group_weight(sentence_count, weighting, temperature));
 
-        index += 1;
+        cursor = end;
+        count += 1;
 
-        if index % 10000 == 0 {
-            info!("Loaded {} groups", index);
+        if count % 10000 == 0 {
+            info!("Indexed {} groups", count);
         }
     }
 
-    Ok(text_data_list)
+    Ok((locations, weights))
 }
 
 impl MyDataService {
-    pub fn new(files: Vec<String>) -> IoResult<Self> {
-        let mut groups = Vec::new();
+    pub fn new(
+        files: Vec<String>,
+        shuffle_seed: u64,
+        weighting: WeightingStrategy,
+        temperature: f32,
+    ) -> IoResult<Self> {
+        let mut backings = Vec::new();
+        let mut index = Vec::new();
         let mut weights = Vec::new();
 
-        for filename in files.iter() {
-            let file = File::open(filename)?;
-            let reader = BufReader::new(file);
+        for (file_id, filename) in files.iter().enumerate() {
+            let backing = load_file_backing(filename)?;
+            let (locations, file_weights) =
+                index_pb_groups(file_id, &backing, weighting, temperature)?;
+            index.extend(locations);
+            weights.extend(file_weights);
+            backings.push(backing);
+        }
 
-            // Assuming read_pb_stream is implemented and it returns an iterator over TextData
-            for text_data in read_pb_stream(reader)? {
-                groups.push(text_data.clone());
-                weights.push(text_data.sentences.len() as f32); // Assuming sentences is a repeated field in TextData
-            }
+        info!(
+            "Indexed {} groups across {} files",
+            index.len(),
+            backings.len()
+        );
+
+        let permutation = build_permutation(&index, shuffle_seed);
+        info!(
+            "Built epoch permutation of {} sentences (shuffle_seed={})",
+            permutation.len(),
+            shuffle_seed
+        );
+
+        let static_weight_sum: f32 = weights.iter().sum();
+        let static_dist = WeightedIndex::new(weights.iter()).ok();
+
+        Ok(MyDataService {
+            files: Arc::new(backings),
+            index: Arc::new(index),
+            static_weight_sum,
+            static_dist: Arc::new(static_dist),
+            epoch: Arc::new(EpochState {
+                permutation_and_cursor: RwLock::new((permutation, 0)),
+            }),
+            live: Arc::new(RwLock::new(LivePool::default())),
+            live_dist_cache: Arc::new(Mutex::new(LiveDistCache::default())),
+        })
+    }
+
+    /// Decodes the single `TextData` group described by `location`.
+    fn decode_group(&self, location: &GroupLocation) -> Result<TextData, Status> {
+        let bytes = &self.files[location.file_id][location.offset..location.offset + location.length];
+        TextData::decode(bytes).map_err(|e| Status::internal(format!("Failed to decode group: {e}")))
+    }
+
+    /// Appends a group ingested from Kafka into the live pool, making it
+    /// immediately eligible for sampling alongside the static `--files` data.
+    fn ingest_live_group(&self, text_data: TextData) {
+        let weight = text_data.sentences.len() as f32; // Assuming sentences is a repeated field in TextData
+        let mut live = self.live.write().unwrap();
+        live.weights.push(weight);
+        live.weight_sum += weight;
+        live.version += 1;
+        live.groups.push(text_data);
+    }
+
+    /// Weighted-picks one group across both the static `--files` index and
+    /// the live Kafka-ingested pool, proportional to each group's weight.
+    ///
+    /// The static `WeightedIndex` is built once in `new`; the live one is
+    /// cached and only rebuilt when `LivePool::version` has moved, so a
+    /// multi-million-group corpus isn't rescanned on every call (important
+    /// for `stream_samples`, which calls this once per emitted batch).
+    fn pick_group(&self, rng: &mut impl Rng) -> Result<TextData, Status> {
+        let live = self.live.read().unwrap();
+        let total_weight = self.static_weight_sum + live.weight_sum;
+
+        if total_weight <= 0.0 {
+            return Err(Status::internal("Failed to select a group"));
         }
 
-        info!("Loaded {} groups", groups.len());
+        if rng.gen::<f32>() * total_weight < self.static_weight_sum {
+            let dist = self
+                .static_dist
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| Status::internal("Failed to select a group"))?;
+            self.decode_group(&self.index[dist.sample(rng)])
+        } else {
+            let mut cache = self.live_dist_cache.lock().unwrap();
+            if cache.version != live.version || cache.dist.is_none() {
+                cache.dist = WeightedIndex::new(live.weights.iter()).ok();
+                cache.version = live.version;
+            }
 
-        Ok(MyDataService { groups, weights })
+            let dist = cache
+                .dist
+                .as_ref()
+                .ok_or_else(|| Status::internal("Failed to select a group"))?;
+            Ok(live.groups[dist.sample(rng)].clone())
+        }
     }
 }
 
@@ -86,29 +351,245 @@ impl DataService for MyDataService {
         let mut num_samples = request.into_inner().num_samples as usize;
         let mut rng = thread_rng();
 
-        let group = self
-            .groups
-            .choose_weighted(&mut rng, |item| item.sentences.len() as f32);
+        let group = self.pick_group(&mut rng)?;
+
+        if num_samples > group.sentences.len() {
+            num_samples = group.sentences.len();
+        }
+
+        let sentences_ref = group
+            .sentences
+            .iter()
+            .choose_multiple(&mut rng, num_samples);
 
-        if group.is_ok() {
-            let group = group.unwrap();
-            if num_samples > group.sentences.len() {
-                num_samples = group.sentences.len();
+        let sentences: Vec<Sentence> = sentences_ref
+            .into_iter()
+            .cloned() // Clone each &Sentence to get Sentence
+            .collect();
+
+        Ok(Response::new(SampledData { samples: sentences }))
+    }
+
+    type StreamSamplesStream = Pin<Box<dyn Stream<Item = Result<SampledData, Status>> + Send>>;
+
+    async fn stream_samples(
+        &self,
+        request: Request<StreamSamplesRequest>,
+    ) -> Result<Response<Self::StreamSamplesStream>, Status> {
+        // Assuming StreamSamplesRequest carries batch_size and total_batches
+        // (0 meaning "stream forever") as fields on the protobuf message.
+        let req = request.into_inner();
+        let batch_size = req.batch_size as usize;
+        let total_batches = req.total_batches;
+
+        let service = self.clone();
+        let (tx, rx) = mpsc::channel(4);
+
+        // thread_rng() is !Send (it wraps an Rc internally) and can't be held
+        // across the .await below, so seed a Send StdRng from it up front.
+        let mut rng = StdRng::from_rng(thread_rng()).map_err(|e| Status::internal(e.to_string()))?;
+
+        tokio::spawn(async move {
+            let mut batches_sent: u32 = 0;
+
+            loop {
+                if total_batches > 0 && batches_sent >= total_batches {
+                    break;
+                }
+
+                let batch = match service.pick_group(&mut rng) {
+                    Ok(group) => {
+                        let num_samples = batch_size.min(group.sentences.len());
+                        let sentences: Vec<Sentence> = group
+                            .sentences
+                            .iter()
+                            .choose_multiple(&mut rng, num_samples)
+                            .into_iter()
+                            .cloned()
+                            .collect();
+
+                        Ok(SampledData { samples: sentences })
+                    }
+                    Err(status) => Err(status),
+                };
+
+                let is_err = batch.is_err();
+                if tx.send(batch).await.is_err() || is_err {
+                    break;
+                }
+
+                batches_sent += 1;
             }
+        });
 
-            let sentences_ref = group
-                .sentences
-                .iter()
-                .choose_multiple(&mut rng, num_samples);
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
 
-            let sentences: Vec<Sentence> = sentences_ref
-                .into_iter()
-                .cloned() // Clone each &Sentence to get Sentence
-                .collect();
+    async fn reset_epoch(
+        &self,
+        request: Request<ResetEpochRequest>,
+    ) -> Result<Response<ResetEpochResponse>, Status> {
+        // Assuming ResetEpochRequest carries the shuffle_seed for the new permutation.
+        let shuffle_seed = request.into_inner().shuffle_seed;
+        let permutation = build_permutation(&self.index, shuffle_seed);
 
-            Ok(Response::new(SampledData { samples: sentences }))
-        } else {
-            Err(Status::internal("Failed to select a group"))
+        *self.epoch.permutation_and_cursor.write().unwrap() = (permutation, 0);
+
+        Ok(Response::new(ResetEpochResponse {}))
+    }
+
+    async fn next_epoch_batch(
+        &self,
+        request: Request<NextEpochBatchRequest>,
+    ) -> Result<Response<EpochBatch>, Status> {
+        // Assuming NextEpochBatchRequest carries num_samples, and EpochBatch
+        // carries samples plus an epoch_complete flag for the caller.
+        let num_samples = request.into_inner().num_samples as usize;
+
+        // A single write-lock section pairs the cursor advance with the
+        // permutation it was read against, so a concurrent reset_epoch can't
+        // be observed as "new permutation, stale cursor" (or vice versa).
+        let (batch_indices, end, total) = {
+            let mut state = self.epoch.permutation_and_cursor.write().unwrap();
+            let (permutation, cursor) = &mut *state;
+            let total = permutation.len();
+            let start = (*cursor).min(total);
+            let end = (start + num_samples).min(total);
+            *cursor = end;
+            (permutation[start..end].to_vec(), end, total)
+        };
+
+        // Consecutive entries can share a group_idx, so cache the
+        // last-decoded group instead of re-decoding its protobuf once per
+        // sentence. Only the most recent group is kept: a group that shows
+        // up again after a different one is decoded again.
+        let mut samples = Vec::with_capacity(batch_indices.len());
+        let mut cached: Option<(u32, TextData)> = None;
+        for (group_idx, sentence_idx) in batch_indices {
+            let group = match &cached {
+                Some((cached_idx, group)) if *cached_idx == group_idx => group,
+                _ => {
+                    let group = self.decode_group(&self.index[group_idx as usize])?;
+                    cached = Some((group_idx, group));
+                    &cached.as_ref().unwrap().1
+                }
+            };
+            samples.push(group.sentences[sentence_idx as usize].clone());
+        }
+
+        Ok(Response::new(EpochBatch {
+            samples,
+            epoch_complete: end >= total,
+        }))
+    }
+}
+
+/// Runs a pprof CPU profiler for `duration_secs`, then writes a pprof-format
+/// protobuf profile to `path` for offline flamegraph/pprof analysis.
+async fn run_profiler(duration_secs: u64, path: &str) -> io::Result<()> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(1000)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    tokio::time::sleep(Duration::from_secs(duration_secs)).await;
+
+    let report = guard
+        .report()
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let profile = report
+        .pprof()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    std::fs::write(path, profile.encode_to_vec())?;
+    info!("Wrote CPU profile to {}", path);
+
+    Ok(())
+}
+
+/// Tries to parse `data` as a sequence of length-delimited `TextData`
+/// records using the same 4-byte-length-prefix framing as the on-disk `.pb`
+/// shards (see `index_pb_groups`). Returns `None` if the framing doesn't
+/// cleanly account for every byte (e.g. a malformed length, or a whole
+/// single-record message that isn't length-prefixed at all).
+fn try_decode_length_delimited(data: &[u8]) -> Option<Vec<TextData>> {
+    let mut records = Vec::new();
+    let mut cursor = 0usize;
+
+    while cursor + 4 <= data.len() {
+        let size = u32::from_le_bytes(data[cursor..cursor + 4].try_into().unwrap()) as usize;
+        let start = cursor + 4;
+        let end = start + size;
+        if end > data.len() {
+            return None;
+        }
+
+        records.push(TextData::decode(&data[start..end]).ok()?);
+        cursor = end;
+    }
+
+    if cursor == data.len() && !records.is_empty() {
+        Some(records)
+    } else {
+        None
+    }
+}
+
+/// Decodes one Kafka message payload into its `TextData` records. A payload
+/// may be a single whole `TextData` protobuf, or multiple length-delimited
+/// records batched together the same way a `.pb` shard is framed.
+fn decode_kafka_payload(payload: &[u8]) -> Result<Vec<TextData>, prost::DecodeError> {
+    if let Some(records) = try_decode_length_delimited(payload) {
+        return Ok(records);
+    }
+
+    TextData::decode(payload).map(|text_data| vec![text_data])
+}
+
+/// Consumes `TextData` records from a Kafka topic and appends them to
+/// `service`'s live pool as they arrive, letting the sample pool grow
+/// alongside the static `--files` corpus. Logs and returns if the consumer
+/// can't be created or subscribed; otherwise runs until the process exits.
+async fn run_kafka_ingest(service: MyDataService, brokers: String, topic: String, group_id: String) {
+    let consumer: StreamConsumer = match ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", &group_id)
+        .set("enable.auto.commit", "true")
+        .create()
+    {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            log::error!("Failed to create Kafka consumer: {e}");
+            return;
+        }
+    };
+
+    if let Err(e) = consumer.subscribe(&[topic.as_str()]) {
+        log::error!("Failed to subscribe to Kafka topic {topic}: {e}");
+        return;
+    }
+
+    info!("Ingesting TextData from Kafka topic {topic} via {brokers}");
+
+    loop {
+        match consumer.recv().await {
+            Ok(message) => {
+                let Some(payload) = message.payload() else {
+                    continue;
+                };
+
+                match decode_kafka_payload(payload) {
+                    Ok(records) => {
+                        for text_data in records {
+                            service.ingest_live_group(text_data);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to decode Kafka message as TextData: {e}"),
+                }
+            }
+            Err(e) => log::warn!("Kafka consumer error: {e}"),
         }
     }
 }
@@ -120,6 +601,34 @@ struct Args {
     /// Files to process
     #[clap(short, long, value_name = "FILE", required = true)]
     files: Vec<String>,
+
+    /// Seed for the epoch-shuffle permutation; random if unset, making full-coverage runs reproducible when given
+    #[clap(long)]
+    shuffle_seed: Option<u64>,
+
+    /// How to weight `--files` groups during sampling; groups ingested from Kafka are always weighted by sentence count
+    #[clap(long, value_enum, default_value = "linear")]
+    weighting: WeightingStrategy,
+
+    /// Temperature for `--weighting temperature` (weight = sentence_count^(1/T))
+    #[clap(long, default_value_t = 1.0)]
+    temperature: f32,
+
+    /// Capture a pprof CPU profile for this many seconds after startup and write it to ./profile.pb
+    #[clap(long, value_name = "SECONDS")]
+    profile: Option<u64>,
+
+    /// Kafka brokers to ingest live TextData records from (e.g. "localhost:9092"), in addition to --files
+    #[clap(long, requires = "kafka_topic")]
+    kafka_brokers: Option<String>,
+
+    /// Kafka topic carrying TextData records to ingest
+    #[clap(long, requires = "kafka_brokers")]
+    kafka_topic: Option<String>,
+
+    /// Kafka consumer group id for live ingestion
+    #[clap(long, default_value = "fish-speech-data-server")]
+    kafka_group_id: String,
 }
 
 #[tokio::main]
@@ -130,7 +639,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     let addr = "[::1]:50051".parse()?;
-    let data_service = MyDataService::new(args.files)?;
+    let shuffle_seed = args.shuffle_seed.unwrap_or_else(|| thread_rng().gen());
+    let data_service =
+        MyDataService::new(args.files, shuffle_seed, args.weighting, args.temperature)?;
+
+    if let Some(duration_secs) = args.profile {
+        tokio::spawn(async move {
+            if let Err(e) = run_profiler(duration_secs, "profile.pb").await {
+                log::error!("Failed to capture CPU profile: {e}");
+            }
+        });
+    }
+
+    if let (Some(brokers), Some(topic)) = (args.kafka_brokers, args.kafka_topic) {
+        let kafka_service = data_service.clone();
+        tokio::spawn(run_kafka_ingest(
+            kafka_service,
+            brokers,
+            topic,
+            args.kafka_group_id,
+        ));
+    }
 
     info!("Starting server at {}", addr);
 
@@ -141,3 +670,144 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_data_with_sentences(n: usize) -> TextData {
+        TextData {
+            sentences: (0..n).map(|_| Sentence::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn encode_length_delimited(records: &[TextData]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for record in records {
+            let bytes = record.encode_to_vec();
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        buf
+    }
+
+    #[test]
+    fn looks_zstd_detects_magic_bytes() {
+        assert!(looks_zstd(&ZSTD_MAGIC, "shard.pb"));
+        assert!(!looks_zstd(&GZIP_MAGIC, "shard.pb"));
+    }
+
+    #[test]
+    fn looks_zstd_detects_extension_without_magic() {
+        assert!(looks_zstd(&[0, 0, 0, 0], "shard.pb.zst"));
+    }
+
+    #[test]
+    fn looks_gzip_detects_magic_bytes() {
+        assert!(looks_gzip(&GZIP_MAGIC, "shard.pb"));
+        assert!(!looks_gzip(&ZSTD_MAGIC, "shard.pb"));
+    }
+
+    #[test]
+    fn looks_gzip_detects_extension_without_magic() {
+        assert!(looks_gzip(&[0, 0], "shard.pb.gz"));
+    }
+
+    #[test]
+    fn index_pb_groups_records_offsets_and_weights() {
+        let records = vec![text_data_with_sentences(3), text_data_with_sentences(5)];
+        let data = encode_length_delimited(&records);
+
+        let (locations, weights) =
+            index_pb_groups(0, &data, WeightingStrategy::Linear, 1.0).unwrap();
+
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0].sentence_count, 3);
+        assert_eq!(locations[1].sentence_count, 5);
+        assert_eq!(weights, vec![3.0, 5.0]);
+
+        let last = &locations[1];
+        let decoded = TextData::decode(&data[last.offset..last.offset + last.length]).unwrap();
+        assert_eq!(decoded.sentences.len(), 5);
+    }
+
+    #[test]
+    fn index_pb_groups_rejects_truncated_record() {
+        let mut data = encode_length_delimited(&[text_data_with_sentences(1)]);
+        data.truncate(data.len() - 1);
+
+        assert!(index_pb_groups(0, &data, WeightingStrategy::Linear, 1.0).is_err());
+    }
+
+    #[test]
+    fn group_weight_strategies() {
+        assert_eq!(group_weight(9, WeightingStrategy::Linear, 1.0), 9.0);
+        assert_eq!(group_weight(9, WeightingStrategy::Sqrt, 1.0), 3.0);
+        assert_eq!(group_weight(9, WeightingStrategy::Uniform, 1.0), 1.0);
+        assert_eq!(
+            group_weight(8, WeightingStrategy::Temperature, 2.0),
+            8f32.powf(0.5)
+        );
+    }
+
+    #[test]
+    fn build_permutation_covers_every_sentence_exactly_once() {
+        let locations = vec![
+            GroupLocation {
+                file_id: 0,
+                offset: 0,
+                length: 0,
+                sentence_count: 2,
+            },
+            GroupLocation {
+                file_id: 0,
+                offset: 0,
+                length: 0,
+                sentence_count: 3,
+            },
+        ];
+
+        let permutation = build_permutation(&locations, 42);
+        assert_eq!(permutation.len(), 5);
+
+        let mut seen = permutation.clone();
+        seen.sort();
+        assert_eq!(seen, vec![(0, 0), (0, 1), (1, 0), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn build_permutation_is_deterministic_for_a_given_seed() {
+        let locations = vec![GroupLocation {
+            file_id: 0,
+            offset: 0,
+            length: 0,
+            sentence_count: 20,
+        }];
+
+        assert_eq!(
+            build_permutation(&locations, 7),
+            build_permutation(&locations, 7)
+        );
+    }
+
+    #[test]
+    fn try_decode_length_delimited_parses_batched_payload() {
+        let records = vec![text_data_with_sentences(1), text_data_with_sentences(2)];
+        let data = encode_length_delimited(&records);
+
+        let decoded = try_decode_length_delimited(&data).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].sentences.len(), 2);
+    }
+
+    #[test]
+    fn decode_kafka_payload_falls_back_to_whole_message() {
+        let record = text_data_with_sentences(4);
+        let data = record.encode_to_vec();
+
+        let decoded = decode_kafka_payload(&data).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].sentences.len(), 4);
+    }
+}